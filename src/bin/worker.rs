@@ -1,27 +1,72 @@
 use clap::{AppSettings, Clap};
 use daemonize::{Daemonize, DaemonizeError};
 use std::collections::HashMap;
-use std::fs::File;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
 
 use detach::serialize;
 
 const PID: &'static str = "/tmp/detach.pid";
 const SOCKET: &'static str = "/tmp/detach.sock";
+const KEY: &'static str = "/tmp/detach.key";
+const SNAPSHOT: &'static str = "/tmp/detach.snapshot";
 const STDOUT: &'static str = "/tmp/detach.out";
 const STDERR: &'static str = "/tmp/detach.err";
 
+/// Schema version of the on-disk snapshot. Bump this whenever the stored
+/// layout changes and add a forward migration in `migrate`.
+const SNAPSHOT_VERSION: u32 = 1;
+
 #[derive(Clap, Debug)]
 #[clap(version = "0.1.0", author = "Aaron R. <superhawk610@gmail.com>")]
 #[clap(setting = AppSettings::ColoredHelp)]
 struct Opts {
+    #[clap(long, about = "Encrypt the transport with ChaCha20-Poly1305.")]
+    encrypt: bool,
+    #[clap(
+        long,
+        default_value = "text",
+        about = "Output format for responses (text or json)."
+    )]
+    format: Format,
     #[clap(subcommand)]
     action: Action,
 }
 
+/// How responses are rendered to stdout. `json` is intended for scripts
+/// that want to consume `get`/`dump`/`set` output programmatically.
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            other => Err(format!("unknown format '{}'", other)),
+        }
+    }
+}
+
 #[derive(Clap, Debug)]
 enum Action {
     #[clap(about = "Spawn a worker in the background.")]
@@ -33,6 +78,7 @@ enum Action {
     Dump,
     #[clap(about = "Close the background worker (if one is open).")]
     Quit,
+    Watch(WatchAction),
 }
 
 #[derive(Clap, Debug)]
@@ -49,6 +95,8 @@ struct SetAction {
     key: String,
     #[clap(index = 2)]
     value: String,
+    #[clap(long = "ex", about = "Expire the key after this many seconds.")]
+    ttl: Option<u64>,
 }
 
 #[derive(Clap, Debug)]
@@ -58,21 +106,42 @@ struct DeleteAction {
     key: String,
 }
 
+#[derive(Clap, Debug)]
+#[clap(about = "Block and print changes to a key as they happen.")]
+struct WatchAction {
+    #[clap(index = 1)]
+    key: String,
+}
+
 fn main() {
-    match handle_command(Opts::parse()) {
-        Ok(_) => (),
-        Err(error) => eprintln!("{}", error),
+    let opts = Opts::parse();
+    let format = opts.format;
+    if let Err(error) = handle_command(opts) {
+        match format {
+            Format::Text => eprintln!("{}", error),
+            Format::Json => println!(
+                "{}",
+                serde_json::json!({ "type": "error", "code": error.to_string() })
+            ),
+        }
     }
 }
 
 fn handle_command(opts: Opts) -> io::Result<()> {
+    let encrypt = opts.encrypt;
+    let format = opts.format;
     match opts.action {
-        Action::Worker => worker_command(),
-        Action::Get(GetAction { key }) => get_command(key),
-        Action::Set(SetAction { key, value }) => set_command(key, value),
-        Action::Delete(DeleteAction { key }) => delete_command(key),
-        Action::Dump => dump_command(),
-        Action::Quit => quit_command(),
+        Action::Worker => worker_command(encrypt),
+        Action::Get(GetAction { key }) => get_command(key, encrypt, format),
+        Action::Set(SetAction {
+            key,
+            value,
+            ttl,
+        }) => set_command(key, value, ttl, encrypt, format),
+        Action::Delete(DeleteAction { key }) => delete_command(key, encrypt, format),
+        Action::Dump => dump_command(encrypt, format),
+        Action::Quit => quit_command(encrypt, format),
+        Action::Watch(WatchAction { key }) => watch_command(key, encrypt, format),
     }
 }
 
@@ -114,59 +183,320 @@ impl std::ops::DerefMut for Socket {
     }
 }
 
-fn connect() -> io::Result<UnixStream> {
-    UnixStream::connect(SOCKET)
+/// features this build understands; advertised during the handshake.
+fn capabilities() -> Vec<String> {
+    vec![
+        "binary".to_string(),
+        "ttl".to_string(),
+        "watch".to_string(),
+    ]
 }
 
-fn send(stream: &mut UnixStream, command: serialize::Command) -> io::Result<()> {
-    stream.write_all(format!("{}\n", command).as_bytes())
+/// Per-connection ChaCha20-Poly1305 state. Each direction keeps its own
+/// monotonic counter; the low 8 bytes of every nonce carry it (little
+/// endian) and the high 4 are fresh randomness. Incoming frames whose
+/// counter doesn't advance are rejected as replays.
+struct Crypto {
+    cipher: ChaCha20Poly1305,
+    send_counter: u64,
+    last_recv_counter: Option<u64>,
 }
 
-fn recv(stream: &mut UnixStream) -> io::Result<serialize::Response> {
-    // most commands (other than SET) should fit in 16 bytes
-    let mut res = String::with_capacity(16);
-    let mut buf_reader = io::BufReader::new(stream);
-    buf_reader.read_line(&mut res)?;
-    res.pop(); // trim trailing newline
+impl Crypto {
+    fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            send_counter: 0,
+            last_recv_counter: None,
+        }
+    }
+
+    /// Encrypt `plaintext` into a `[12-byte nonce][ciphertext||tag]` record.
+    fn seal(&mut self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let counter = self.send_counter;
+        self.send_counter += 1;
+
+        let mut nonce = [0u8; 12];
+        nonce[..8].copy_from_slice(&counter.to_le_bytes());
+        rand::thread_rng().fill_bytes(&mut nonce[8..]);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failure"))?;
+
+        let mut record = Vec::with_capacity(nonce.len() + ciphertext.len());
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&ciphertext);
+        Ok(record)
+    }
+
+    /// Verify and decrypt a record produced by `seal`, rejecting replays.
+    fn open(&mut self, record: &[u8]) -> io::Result<Vec<u8>> {
+        if record.len() < 12 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated record"));
+        }
+
+        let nonce = &record[..12];
+        let counter = u64::from_le_bytes(nonce[..8].try_into().unwrap());
+        if let Some(last) = self.last_recv_counter {
+            if counter <= last {
+                return Err(io::Error::new(io::ErrorKind::Other, "replayed frame"));
+            }
+        }
+
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), &record[12..])
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "tag verification failed"))?;
 
-    res.parse()
-        .map_err(|_| io::Error::new(io::ErrorKind::Other, "parse error"))
+        self.last_recv_counter = Some(counter);
+        Ok(plaintext)
+    }
+}
+
+/// A protocol connection, optionally wrapped in an AEAD layer. Frames are
+/// written verbatim when plaintext, or as a length-prefixed AEAD record
+/// when encrypted.
+struct Session {
+    stream: UnixStream,
+    crypto: Option<Crypto>,
 }
 
-fn command(cmd: serialize::Command) -> io::Result<()> {
-    let mut stream = connect()?;
-    send(&mut stream, cmd)?;
+impl Session {
+    fn new(stream: UnixStream, crypto: Option<Crypto>) -> Self {
+        Self { stream, crypto }
+    }
+
+    /// Clone the underlying socket so the worker can push notifications to
+    /// a watcher from another thread.
+    fn try_clone_stream(&self) -> io::Result<UnixStream> {
+        self.stream.try_clone()
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.crypto.is_some()
+    }
 
-    let res = recv(&mut stream)?;
-    println!("{}", res);
+    fn write_payload(&mut self, payload: &[u8]) -> io::Result<()> {
+        match self.crypto {
+            Some(ref mut crypto) => {
+                let record = crypto.seal(payload)?;
+                self.stream.write_all(&(record.len() as u32).to_le_bytes())?;
+                self.stream.write_all(&record)?;
+            }
+            None => self.stream.write_all(payload)?,
+        }
+        self.stream.flush()
+    }
+
+    /// Read one encrypted record and return its plaintext. Only valid in
+    /// encrypted mode; plaintext frames are parsed straight off the stream.
+    fn read_record(&mut self) -> io::Result<Vec<u8>> {
+        let mut len = [0u8; 4];
+        self.stream.read_exact(&mut len)?;
+        // the length prefix is unauthenticated (it precedes the AEAD tag),
+        // so bound it before allocating rather than trust an attacker's u32
+        let len = u32::from_le_bytes(len) as usize;
+        if len > serialize::MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "record length exceeds maximum",
+            ));
+        }
+        let mut record = vec![0u8; len];
+        self.stream.read_exact(&mut record)?;
+        self.crypto
+            .as_mut()
+            .expect("read_record without crypto")
+            .open(&record)
+    }
+
+    fn write_command(&mut self, command: serialize::Command) -> io::Result<()> {
+        let mut buf = Vec::new();
+        command.write_frame(&mut buf)?;
+        self.write_payload(&buf)
+    }
+
+    fn write_response(&mut self, response: serialize::Response) -> io::Result<()> {
+        let mut buf = Vec::new();
+        response.write_frame(&mut buf)?;
+        self.write_payload(&buf)
+    }
+
+    fn read_command(&mut self) -> io::Result<serialize::Command> {
+        if self.crypto.is_some() {
+            let buf = self.read_record()?;
+            serialize::Command::read_frame(&mut &buf[..])
+        } else {
+            serialize::Command::read_frame(&mut self.stream)
+        }
+    }
+
+    fn read_response(&mut self) -> io::Result<serialize::Response> {
+        if self.crypto.is_some() {
+            let buf = self.read_record()?;
+            serialize::Response::read_frame(&mut &buf[..])
+        } else {
+            serialize::Response::read_frame(&mut self.stream)
+        }
+    }
+
+    /// Client side of the version handshake; bails out with a clear error
+    /// if the major versions disagree.
+    fn handshake(&mut self) -> io::Result<()> {
+        self.write_command(serialize::Command::Hello {
+            version: serialize::PROTOCOL_VERSION,
+            capabilities: capabilities(),
+        })?;
+
+        match self.read_response()? {
+            serialize::Response::Hello { version, .. } => {
+                if serialize::protocol_major(version)
+                    != serialize::protocol_major(serialize::PROTOCOL_VERSION)
+                {
+                    Err(io::Error::new(io::ErrorKind::Other, "version mismatch"))
+                } else {
+                    Ok(())
+                }
+            }
+            serialize::Response::Err(error) => Err(io::Error::new(io::ErrorKind::Other, error)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "unexpected handshake response",
+            )),
+        }
+    }
+}
+
+fn connect(encrypt: bool) -> io::Result<Session> {
+    let stream = UnixStream::connect(SOCKET)?;
+    let crypto = if encrypt {
+        Some(Crypto::new(&load_key()?))
+    } else {
+        None
+    };
+
+    let mut session = Session::new(stream, crypto);
+    session.handshake()?;
+    Ok(session)
+}
+
+/// Read the worker's session key, which it persists next to the pidfile.
+fn load_key() -> io::Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    File::open(KEY)?.read_exact(&mut key)?;
+    Ok(key)
+}
+
+fn command(cmd: serialize::Command, encrypt: bool, format: Format) -> io::Result<()> {
+    // dumps already carry a full JSON object, so they're rendered verbatim
+    // rather than wrapped in the `{"type":"value",...}` envelope.
+    let is_dump = matches!(cmd, serialize::Command::Dump);
+
+    let mut session = connect(encrypt)?;
+    session.write_command(cmd)?;
+
+    let res = session.read_response()?;
+    match format {
+        Format::Text => println!("{}", res),
+        Format::Json => println!("{}", render_json(res, is_dump)),
+    }
 
     Ok(())
 }
 
-fn get_command(key: String) -> io::Result<()> {
-    command(serialize::Command::Get { key })
+/// Render a response as a stable JSON document for `--format json`.
+fn render_json(res: serialize::Response, is_dump: bool) -> String {
+    use serde_json::json;
+
+    let value = match res {
+        serialize::Response::Value(value) => {
+            let inner = value.into_inner();
+            // the worker serializes dumps as a JSON object already
+            if is_dump {
+                return inner;
+            }
+            json!({ "type": "value", "value": inner })
+        }
+        serialize::Response::Err(error) => json!({ "type": "error", "code": error }),
+        serialize::Response::Ok => json!({ "type": "ok" }),
+        serialize::Response::Hello {
+            version,
+            capabilities,
+        } => json!({ "type": "hello", "version": version, "capabilities": capabilities }),
+        serialize::Response::Event { key, value } => {
+            json!({ "type": "event", "key": key, "value": value.into_inner() })
+        }
+    };
+
+    value.to_string()
+}
+
+fn get_command(key: String, encrypt: bool, format: Format) -> io::Result<()> {
+    command(serialize::Command::Get { key }, encrypt, format)
 }
 
-fn set_command(key: String, value: String) -> io::Result<()> {
-    command(serialize::Command::Set {
-        key,
-        value: serialize::WrappedValue::from_string(value),
-    })
+fn set_command(
+    key: String,
+    value: String,
+    ttl: Option<u64>,
+    encrypt: bool,
+    format: Format,
+) -> io::Result<()> {
+    command(
+        serialize::Command::Set {
+            key,
+            value: serialize::WrappedValue::from_string(value),
+            ttl,
+        },
+        encrypt,
+        format,
+    )
 }
 
-fn delete_command(key: String) -> io::Result<()> {
-    command(serialize::Command::Delete { key })
+fn delete_command(key: String, encrypt: bool, format: Format) -> io::Result<()> {
+    command(serialize::Command::Delete { key }, encrypt, format)
 }
 
-fn dump_command() -> io::Result<()> {
-    command(serialize::Command::Dump)
+fn dump_command(encrypt: bool, format: Format) -> io::Result<()> {
+    command(serialize::Command::Dump, encrypt, format)
 }
 
-fn quit_command() -> io::Result<()> {
-    command(serialize::Command::Quit)
+fn quit_command(encrypt: bool, format: Format) -> io::Result<()> {
+    command(serialize::Command::Quit, encrypt, format)
 }
 
-fn worker_command() -> io::Result<()> {
+/// Subscribe to a key and print each pushed change until the connection
+/// closes (or we're interrupted).
+fn watch_command(key: String, encrypt: bool, format: Format) -> io::Result<()> {
+    let mut session = connect(encrypt)?;
+    session.write_command(serialize::Command::Watch { key })?;
+
+    loop {
+        match session.read_response() {
+            // the server rejects some subscriptions outright (e.g. on an
+            // encrypted connection); print the reason and stop watching
+            Ok(res @ serialize::Response::Err(_)) => {
+                match format {
+                    Format::Text => println!("{}", res),
+                    Format::Json => println!("{}", render_json(res, false)),
+                }
+                break;
+            }
+            Ok(res) => match format {
+                Format::Text => println!("{}", res),
+                Format::Json => println!("{}", render_json(res, false)),
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+fn worker_command(encrypt: bool) -> io::Result<()> {
     let stdout = File::create(STDOUT).unwrap();
     let stderr = File::create(STDERR).unwrap();
 
@@ -178,7 +508,7 @@ fn worker_command() -> io::Result<()> {
         .exit_action(|| println!("started background worker"));
 
     match daemonize.start() {
-        Ok(_) => start_socket()?,
+        Ok(_) => start_socket(encrypt)?,
         Err(DaemonizeError::LockPidfile(_)) => eprintln!("server already running"),
         Err(e) => eprintln!("oh no! {}", e),
     }
@@ -186,29 +516,172 @@ fn worker_command() -> io::Result<()> {
     Ok(())
 }
 
+/// Generate a fresh 32-byte session key and persist it next to the pidfile
+/// with owner-only permissions so only this user can read it.
+fn generate_key() -> io::Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(KEY)?;
+    file.write_all(&key)?;
+
+    Ok(key)
+}
+
+/// A stored value and the instant at which it expires (if any).
+type Entry = (String, Option<Instant>);
+
 #[derive(Debug, Default)]
 struct AppState {
-    count: u8,
-    db: HashMap<String, String>,
+    count: u64,
+    db: HashMap<String, Entry>,
     should_terminate: bool,
+    /// open streams subscribed to each key, pushed an `Event` whenever the
+    /// key is set or deleted
+    watchers: HashMap<String, Vec<UnixStream>>,
+}
+
+/// Atomically persist the database to the snapshot file, writing to a
+/// sibling temp file first and renaming over the target so a crash mid-
+/// write can never leave a half-written snapshot behind.
+///
+/// `Instant` deadlines aren't meaningful across a restart, so only the
+/// key/value pairs are persisted; entries loaded from a snapshot have no
+/// expiration.
+fn save_snapshot(db: &HashMap<String, Entry>) -> io::Result<()> {
+    let values: HashMap<&String, &String> = db.iter().map(|(k, (v, _))| (k, v)).collect();
+
+    let mut buf = SNAPSHOT_VERSION.to_le_bytes().to_vec();
+    let body = bincode::serialize(&values)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("serialize: {}", e)))?;
+    buf.extend_from_slice(&body);
+
+    let tmp = format!("{}.tmp", SNAPSHOT);
+    fs::write(&tmp, &buf)?;
+    fs::rename(&tmp, SNAPSHOT)
+}
+
+/// Load the snapshot written by `save_snapshot`, migrating older layouts
+/// forward to the current one. An absent snapshot is an empty database.
+fn load_snapshot() -> io::Result<HashMap<String, String>> {
+    let data = match fs::read(SNAPSHOT) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    if data.len() < 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "snapshot is missing its version header",
+        ));
+    }
+
+    let version = u32::from_le_bytes(data[..4].try_into().unwrap());
+    migrate(version, &data[4..])
+}
+
+/// Upgrade a snapshot body tagged with `version` to the current layout.
+/// Newer-than-supported snapshots are refused rather than risking a
+/// lossy downgrade.
+fn migrate(version: u32, body: &[u8]) -> io::Result<HashMap<String, String>> {
+    if version > SNAPSHOT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "snapshot version {} is newer than this build supports ({})",
+                version, SNAPSHOT_VERSION
+            ),
+        ));
+    }
+
+    match version {
+        // v1: raw bincode of the key/value map
+        1 => bincode::deserialize(body)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("deserialize: {}", e))),
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown snapshot version {}", other),
+        )),
+    }
 }
 
-fn start_socket() -> io::Result<()> {
+/// Drop every entry whose deadline has passed, persisting afterwards if
+/// anything was removed so the snapshot doesn't resurrect expired keys.
+/// Watchers of an expired key are notified just like on a delete; the
+/// pushes happen after the lock is released.
+fn sweep_expired(state: &Arc<Mutex<AppState>>) {
+    let expired: Vec<String> = {
+        let mut state = state.lock().unwrap();
+        let now = Instant::now();
+        let expired: Vec<String> = state
+            .db
+            .iter()
+            .filter(|(_, (_, deadline))| deadline.map_or(false, |d| d <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired {
+            state.db.remove(key);
+        }
+
+        if !expired.is_empty() {
+            if let Err(e) = save_snapshot(&state.db) {
+                eprintln!("unable to persist snapshot: {}", e);
+            }
+        }
+
+        expired
+    };
+
+    for key in expired {
+        notify_watchers(state, &key, "");
+    }
+}
+
+fn start_socket(encrypt: bool) -> io::Result<()> {
     println!("I'm a worker!");
 
+    let key = if encrypt { Some(generate_key()?) } else { None };
     let listener = Socket::bind(SOCKET).expect("unable to bind to socket");
-    let mut state = AppState::default();
-
+    listener.set_nonblocking(true)?;
+    let state = Arc::new(Mutex::new(AppState {
+        db: load_snapshot()?
+            .into_iter()
+            .map(|(k, v)| (k, (v, None)))
+            .collect(),
+        ..AppState::default()
+    }));
+
+    // the listener is nonblocking, so poll it with a one-second timeout;
+    // this wakes the loop often enough to actively sweep expired keys even
+    // when no client ever connects. each connection is handled on its own
+    // thread so long-lived watchers and normal traffic can coexist.
     loop {
-        match listener.accept() {
-            Ok((socket, addr)) => {
-                eprintln!("got connection {:?}", addr);
-                accept_connection(socket, &mut state)?;
+        if wait_readable(listener.as_raw_fd(), 1000)? {
+            match listener.accept() {
+                Ok((socket, addr)) => {
+                    eprintln!("got connection {:?}", addr);
+                    let session = Session::new(socket, key.map(|k| Crypto::new(&k)));
+                    let state = Arc::clone(&state);
+                    thread::spawn(move || {
+                        if let Err(e) = handle_connection(session, &state) {
+                            eprintln!("connection error: {}", e);
+                        }
+                    });
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => eprintln!("unable to accept connection {:?}", e),
             }
-            Err(e) => eprintln!("unable to accept connection {:?}", e),
         }
 
-        if state.should_terminate {
+        sweep_expired(&state);
+        if state.lock().unwrap().should_terminate {
             break;
         }
     }
@@ -216,42 +689,260 @@ fn start_socket() -> io::Result<()> {
     Ok(())
 }
 
-fn accept_connection(mut socket: UnixStream, state: &mut AppState) -> io::Result<()> {
-    let mut req = String::with_capacity(16);
-    let mut buf_reader = io::BufReader::new(&mut socket);
-    buf_reader.read_line(&mut req)?;
-    req.pop(); // remove trailing newline
+/// Block until `fd` is readable or `timeout_ms` elapses, returning whether
+/// it became readable.
+fn wait_readable(fd: i32, timeout_ms: i32) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
 
-    let res = match req
-        .parse()
-        .map_err(|_| io::Error::new(io::ErrorKind::Other, "parse error"))?
-    {
+    let ready = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+    if ready < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(ready > 0)
+}
+
+fn handle_connection(mut session: Session, state: &Arc<Mutex<AppState>>) -> io::Result<()> {
+    // every client greets us with a version handshake before issuing
+    // its real commands; reject incompatible majors outright.
+    match session.read_command()? {
+        serialize::Command::Hello { version, .. } => {
+            if serialize::protocol_major(version)
+                != serialize::protocol_major(serialize::PROTOCOL_VERSION)
+            {
+                session.write_response(serialize::Response::Err("version mismatch".to_string()))?;
+                return Ok(());
+            }
+
+            session.write_response(serialize::Response::Hello {
+                version: serialize::PROTOCOL_VERSION,
+                capabilities: capabilities(),
+            })?;
+        }
+        // tolerate a client that skips the handshake by replaying its
+        // first frame through the command loop below
+        other => dispatch(other, &mut session, state)?,
+    }
+
+    // keep the connection open and serve commands until the client hangs
+    // up; watchers simply never send another command and block on reads.
+    loop {
+        match session.read_command() {
+            Ok(command) => dispatch(command, &mut session, state)?,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle a single command on an established connection, writing the
+/// response (if any). On a plaintext connection `Watch` registers the
+/// stream and returns without a response, leaving the connection open for
+/// pushed events; on an encrypted one it is answered with an error.
+fn dispatch(
+    command: serialize::Command,
+    session: &mut Session,
+    state: &Arc<Mutex<AppState>>,
+) -> io::Result<()> {
+    if let serialize::Command::Watch { key } = command {
+        // Events are pushed as plaintext frames to a bare clone of the
+        // socket, which has no access to this connection's sealing
+        // counter, so they can't be encrypted. Refuse rather than leave
+        // an encrypted watcher hanging on frames it can never decode.
+        if session.is_encrypted() {
+            return session.write_response(serialize::Response::Err(
+                "watch is not supported on encrypted connections".to_string(),
+            ));
+        }
+
+        let stream = session.try_clone_stream()?;
+        state.lock().unwrap().watchers.entry(key).or_default().push(stream);
+        return Ok(());
+    }
+
+    // Apply the command under the lock, but defer any watcher pushes until
+    // the guard is dropped: a stalled watcher must never block other
+    // clients or the expiry sweeper.
+    let (res, event) = {
+        let mut state = state.lock().unwrap();
+        let out = process_command(command, &mut state);
+        state.count += 1;
+        out
+    };
+
+    if let Some((key, value)) = event {
+        notify_watchers(state, &key, &value);
+    }
+
+    session.write_response(res)
+}
+
+/// Apply a command to the shared state and produce its response, along with
+/// an optional `(key, value)` change that watchers of that key should be
+/// notified of once the state lock is released.
+fn process_command(
+    command: serialize::Command,
+    state: &mut AppState,
+) -> (serialize::Response, Option<(String, String)>) {
+    match command {
+        serialize::Command::Hello { .. } => (
+            serialize::Response::Err("unexpected hello".to_string()),
+            None,
+        ),
+        serialize::Command::Watch { .. } => (
+            // handled by `dispatch` before we ever get here
+            serialize::Response::Err("unexpected watch".to_string()),
+            None,
+        ),
         serialize::Command::Get { key } => {
-            serialize::Response::Value(serialize::WrappedValue::from_string(
-                state.db.get(&key).cloned().unwrap_or_else(String::new),
-            ))
+            // lazily evict the entry if its deadline has already passed,
+            // surfacing the expiry to watchers just like a delete
+            let expired = matches!(
+                state.db.get(&key),
+                Some((_, Some(deadline))) if *deadline <= Instant::now()
+            );
+            if expired {
+                state.db.remove(&key);
+            }
+
+            let response = serialize::Response::Value(serialize::WrappedValue::from_string(
+                state
+                    .db
+                    .get(&key)
+                    .map(|(value, _)| value.clone())
+                    .unwrap_or_else(String::new),
+            ));
+            let event = if expired { Some((key, String::new())) } else { None };
+            (response, event)
         }
-        serialize::Command::Set { key, value } => {
-            state.db.insert(key, value.into_inner());
-            serialize::Response::Ok
+        serialize::Command::Set { key, value, ttl } => {
+            let deadline = ttl.map(|secs| Instant::now() + Duration::from_secs(secs));
+            let value = value.into_inner();
+            state.db.insert(key.clone(), (value.clone(), deadline));
+            if let Err(e) = save_snapshot(&state.db) {
+                eprintln!("unable to persist snapshot: {}", e);
+            }
+            (serialize::Response::Ok, Some((key, value)))
         }
         serialize::Command::Delete { key } => {
             state.db.remove(&key);
-            serialize::Response::Ok
+            if let Err(e) = save_snapshot(&state.db) {
+                eprintln!("unable to persist snapshot: {}", e);
+            }
+            (serialize::Response::Ok, Some((key, String::new())))
+        }
+        serialize::Command::Dump => {
+            let now = Instant::now();
+            let map: HashMap<&String, &String> = state
+                .db
+                .iter()
+                .filter(|(_, (_, deadline))| deadline.map_or(true, |d| d > now))
+                .map(|(key, (value, _))| (key, value))
+                .collect();
+
+            let response = serialize::Response::Value(serialize::WrappedValue::from_string(
+                serde_json::to_string(&map).unwrap_or_else(|_| "{}".to_string()),
+            ));
+            (response, None)
         }
-        serialize::Command::Dump => serialize::Response::Value(
-            serialize::WrappedValue::from_string(format!("{:?}", state.db)),
-        ),
         serialize::Command::Quit => {
             state.should_terminate = true;
-            serialize::Response::Ok
+            (serialize::Response::Ok, None)
+        }
+    }
+}
+
+/// Push an `Event` frame to every stream watching `key`, dropping any that
+/// error (the subscriber has gone away). Clone the subscriber handles under
+/// a brief lock and write to the clones with the guard dropped, so the
+/// socket writes never block other clients and a concurrent writer on the
+/// same key can't lose its own notification (the registry is only pruned,
+/// afterwards, of the streams whose write failed).
+fn notify_watchers(state: &Arc<Mutex<AppState>>, key: &str, value: &str) {
+    let clones: Vec<(i32, UnixStream)> = {
+        let state = state.lock().unwrap();
+        match state.watchers.get(key) {
+            // clone under the lock, remembering each registered stream's fd;
+            // a failed clone is simply skipped
+            Some(streams) => streams
+                .iter()
+                .filter_map(|s| s.try_clone().ok().map(|c| (s.as_raw_fd(), c)))
+                .collect(),
+            None => return,
         }
     };
+    if clones.is_empty() {
+        return;
+    }
 
-    socket.write_all(format!("{}\n", res).as_bytes())?;
+    let event = serialize::Response::Event {
+        key: key.to_string(),
+        value: serialize::WrappedValue::from_string(value.to_string()),
+    };
+    let mut frame = Vec::new();
+    if event.write_frame(&mut frame).is_err() {
+        return;
+    }
 
-    state.count += 1;
-    eprintln!("state: {:?}", state);
+    let mut gone = Vec::new();
+    for (fd, mut stream) in clones {
+        // the clone shares the subscriber's socket; a write error just means
+        // that subscriber has gone away
+        if stream.write_all(&frame).and_then(|_| stream.flush()).is_err() {
+            gone.push(fd);
+        }
+    }
 
-    Ok(())
+    if !gone.is_empty() {
+        if let Some(streams) = state.lock().unwrap().watchers.get_mut(key) {
+            streams.retain(|s| !gone.contains(&s.as_raw_fd()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_and_open_roundtrip() {
+        let key = [7u8; 32];
+        let mut sender = Crypto::new(&key);
+        let mut receiver = Crypto::new(&key);
+
+        let record = sender.seal(b"hello").unwrap();
+        assert_eq!(receiver.open(&record).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn replayed_frame_is_rejected() {
+        let key = [9u8; 32];
+        let mut sender = Crypto::new(&key);
+        let mut receiver = Crypto::new(&key);
+
+        let first = sender.seal(b"one").unwrap();
+        let second = sender.seal(b"two").unwrap();
+
+        // accepting the second frame advances the last-seen counter...
+        assert_eq!(receiver.open(&second).unwrap(), b"two");
+        // ...so replaying the earlier (lower-counter) frame is refused
+        assert!(receiver.open(&first).is_err());
+    }
+
+    #[test]
+    fn tampered_ciphertext_fails_the_tag_check() {
+        let key = [3u8; 32];
+        let mut sender = Crypto::new(&key);
+        let mut receiver = Crypto::new(&key);
+
+        let mut record = sender.seal(b"secret").unwrap();
+        *record.last_mut().unwrap() ^= 0xff;
+        assert!(receiver.open(&record).is_err());
+    }
 }
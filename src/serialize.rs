@@ -1,13 +1,19 @@
 use std::fmt::Display;
+use std::io::{self, Read, Write};
 use std::str::FromStr;
 
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
 ///  # commands
 ///
 ///   GET <key>
-///   SET <key> VAL n <value>
+///   SET <key> [EX <secs>] VAL n <value>
 ///   DEL <key>
 ///   DMP
 ///   EXT
+///   WCH <key>
 ///
 /// # responses
 ///
@@ -15,27 +21,76 @@ use std::str::FromStr;
 ///   VAL 0
 ///   ERR code
 ///   OK
+///   EVT <key> VAL n <value>
 ///
 /// # format
 ///
 /// all commands/responses are newline terminated; VALUE may
 /// contain internal newlines, and specifies value length to
 /// insure that all bytes are read
+///
+/// the text protocol above can't round-trip values that contain
+/// a newline (the reader stops at the first one), so a binary
+/// framing codec is also provided via `read_frame`/`write_frame`:
+/// a single opcode byte, then VarInt-encoded (7 bits/byte, high
+/// bit continues, little-endian) lengths followed by raw bytes.
+/// large SET/VAL payloads are zlib-compressed past a threshold.
+///
+/// the socket transport always uses the binary codec (see
+/// `Session` in the worker); the `Display`/`FromStr` text encoders
+/// are retained only for human-readable logging and debugging, not
+/// for on-the-wire framing, so they never see the newline hazard.
+
+/// the protocol version this build speaks; bumped whenever the wire
+/// format changes in a way that isn't backward compatible. the high
+/// 16 bits are the major version (checked during the handshake), the
+/// low 16 bits are the minor version.
+pub const PROTOCOL_VERSION: u32 = 0x0001_0002;
+
+/// extract the major version for compatibility checks.
+pub fn protocol_major(version: u32) -> u32 {
+    version >> 16
+}
 
 #[derive(Debug)]
 pub enum Command {
-    Get { key: String },
-    Set { key: String, value: WrappedValue },
-    Delete { key: String },
+    Hello {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: WrappedValue,
+        /// optional time-to-live, in seconds, after which the worker
+        /// treats the entry as absent
+        ttl: Option<u64>,
+    },
+    Delete {
+        key: String,
+    },
     Dump,
     Quit,
+    Watch {
+        key: String,
+    },
 }
 
 #[derive(Debug)]
 pub enum Response {
+    Hello {
+        version: u32,
+        capabilities: Vec<String>,
+    },
     Value(WrappedValue),
     Err(String),
     Ok,
+    Event {
+        key: String,
+        value: WrappedValue,
+    },
 }
 
 #[derive(Debug)]
@@ -59,6 +114,20 @@ impl WrappedValue {
         }
     }
 
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self {
+            len: bytes.len(),
+            buf: Some(bytes),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self.buf {
+            Some(ref buf) => &buf[..self.len],
+            None => &[],
+        }
+    }
+
     pub fn into_inner(self) -> String {
         if let Some(buf) = self.buf {
             String::from_utf8(buf[..self.len].to_vec()).unwrap()
@@ -68,6 +137,19 @@ impl WrappedValue {
     }
 }
 
+/// parse the `<version> <cap> <cap> ...` tail shared by the `HEL`
+/// command and response.
+fn parse_hello(rest: &str) -> Result<(u32, Vec<String>), ParseError> {
+    let mut parts = rest.split_whitespace();
+    let version = parts
+        .next()
+        .ok_or(ParseError)?
+        .parse()
+        .map_err(|_| ParseError)?;
+    let capabilities = parts.map(|s| s.to_string()).collect();
+    Ok((version, capabilities))
+}
+
 impl FromStr for Command {
     type Err = ParseError;
 
@@ -75,24 +157,38 @@ impl FromStr for Command {
         use Command::*;
 
         match &value[..3] {
+            "HEL" => parse_hello(&value[4..]).map(|(version, capabilities)| Hello {
+                version,
+                capabilities,
+            }),
             "GET" => Ok(Get {
                 key: value[4..].to_string(),
             }),
             "SET" => {
-                if let Some((key, value)) = value[4..].split_once(' ') {
-                    Ok(Set {
-                        key: key.into(),
-                        value: value.parse()?,
-                    })
-                } else {
-                    Err(ParseError)
+                let (key, mut rest) = value[4..].split_once(' ').ok_or(ParseError)?;
+
+                // an optional `EX <secs>` clause precedes the value
+                let mut ttl = None;
+                if let Some(after) = rest.strip_prefix("EX ") {
+                    let (secs, tail) = after.split_once(' ').ok_or(ParseError)?;
+                    ttl = Some(secs.parse().map_err(|_| ParseError)?);
+                    rest = tail;
                 }
+
+                Ok(Set {
+                    key: key.into(),
+                    value: rest.parse()?,
+                    ttl,
+                })
             }
             "DEL" => Ok(Delete {
                 key: value[4..].to_string(),
             }),
             "DMP" => Ok(Dump),
             "EXT" => Ok(Quit),
+            "WCH" => Ok(Watch {
+                key: value[4..].to_string(),
+            }),
             _ => Err(ParseError),
         }
     }
@@ -103,9 +199,20 @@ impl FromStr for Response {
 
     fn from_str(value: &str) -> Result<Self, <Self as FromStr>::Err> {
         match &value[..2] {
+            "HE" => parse_hello(&value[4..]).map(|(version, capabilities)| Response::Hello {
+                version,
+                capabilities,
+            }),
             "OK" => Ok(Response::Ok),
             "ER" => Ok(Response::Err(value[4..].to_string())),
             "VA" => Ok(Response::Value(value.parse()?)),
+            "EV" => {
+                let (key, rest) = value[4..].split_once(' ').ok_or(ParseError)?;
+                Ok(Response::Event {
+                    key: key.to_string(),
+                    value: rest.parse()?,
+                })
+            }
             _ => Err(ParseError),
         }
     }
@@ -139,11 +246,25 @@ impl FromStr for WrappedValue {
 impl Display for Command {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match *self {
+            Command::Hello {
+                version,
+                ref capabilities,
+            } => write!(f, "HEL {} {}", version, capabilities.join(" ")),
             Command::Get { ref key } => write!(f, "GET {}", key),
-            Command::Set { ref key, ref value } => write!(f, "SET {} {}", key, value),
+            Command::Set {
+                ref key,
+                ref value,
+                ttl: Some(secs),
+            } => write!(f, "SET {} EX {} {}", key, secs, value),
+            Command::Set {
+                ref key,
+                ref value,
+                ttl: None,
+            } => write!(f, "SET {} {}", key, value),
             Command::Delete { ref key } => write!(f, "DEL {}", key),
             Command::Dump => write!(f, "DMP"),
             Command::Quit => write!(f, "EXT"),
+            Command::Watch { ref key } => write!(f, "WCH {}", key),
         }
     }
 }
@@ -151,9 +272,17 @@ impl Display for Command {
 impl Display for Response {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match *self {
+            Response::Hello {
+                version,
+                ref capabilities,
+            } => write!(f, "HEL {} {}", version, capabilities.join(" ")),
             Response::Value(ref value) => write!(f, "{}", value),
             Response::Err(ref error) => write!(f, "ERR {}", error),
             Response::Ok => write!(f, "OK"),
+            Response::Event {
+                ref key,
+                ref value,
+            } => write!(f, "EVT {} {}", key, value),
         }
     }
 }
@@ -173,3 +302,431 @@ impl Display for WrappedValue {
         }
     }
 }
+
+/// frame opcodes for the binary codec; these mirror the three-letter
+/// verbs used by the text protocol above.
+mod opcode {
+    pub const HEL: u8 = 0x00;
+    pub const GET: u8 = 0x01;
+    pub const SET: u8 = 0x02;
+    pub const DEL: u8 = 0x03;
+    pub const DMP: u8 = 0x04;
+    pub const EXT: u8 = 0x05;
+    pub const VAL: u8 = 0x06;
+    pub const ERR: u8 = 0x07;
+    pub const OK: u8 = 0x08;
+    pub const WCH: u8 = 0x09;
+    pub const EVT: u8 = 0x0a;
+}
+
+/// values at least this large are zlib-compressed on the wire
+pub const COMPRESS_THRESHOLD: usize = 256;
+
+/// upper bound on any single length read off the wire, in bytes. Lengths
+/// are attacker-controlled VarInts, so we refuse anything larger rather
+/// than hand the figure straight to an allocator (a bogus `u64::MAX` would
+/// otherwise abort the daemon).
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// zlib tops out around 1000:1, so an `uncompressed` length far larger than
+/// the compressed blob could produce is a lie; reject it before inflating.
+const MAX_COMPRESS_RATIO: usize = 1024;
+
+/// Reject a length read off the wire that exceeds `MAX_FRAME_LEN` before it
+/// reaches an allocator.
+fn checked_len(n: u64) -> io::Result<usize> {
+    if n > MAX_FRAME_LEN as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame length exceeds maximum",
+        ));
+    }
+    Ok(n as usize)
+}
+
+fn write_varint(w: &mut impl Write, mut n: u64) -> io::Result<()> {
+    loop {
+        let mut byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n != 0 {
+            byte |= 0x80;
+        }
+        w.write_all(&[byte])?;
+        if n == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn read_varint(r: &mut impl Read) -> io::Result<u64> {
+    let mut result = 0u64;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0u8; 1];
+        r.read_exact(&mut byte)?;
+        result |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    Ok(result)
+}
+
+fn write_string(w: &mut impl Write, s: &str) -> io::Result<()> {
+    write_varint(w, s.len() as u64)?;
+    w.write_all(s.as_bytes())
+}
+
+fn read_string(r: &mut impl Read) -> io::Result<String> {
+    let len = checked_len(read_varint(r)?)?;
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid utf-8"))
+}
+
+fn write_ttl(w: &mut impl Write, ttl: Option<u64>) -> io::Result<()> {
+    match ttl {
+        Some(secs) => {
+            w.write_all(&[1])?;
+            write_varint(w, secs)
+        }
+        None => w.write_all(&[0]),
+    }
+}
+
+fn read_ttl(r: &mut impl Read) -> io::Result<Option<u64>> {
+    let mut flag = [0u8; 1];
+    r.read_exact(&mut flag)?;
+    match flag[0] {
+        0 => Ok(None),
+        1 => Ok(Some(read_varint(r)?)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid ttl flag")),
+    }
+}
+
+fn write_value(w: &mut impl Write, value: &WrappedValue) -> io::Result<()> {
+    let bytes = value.as_bytes();
+    if bytes.len() >= COMPRESS_THRESHOLD {
+        // 1 => compressed: uncompressed len, then the deflated blob
+        w.write_all(&[1])?;
+        write_varint(w, bytes.len() as u64)?;
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(bytes)?;
+        let blob = encoder.finish()?;
+        write_varint(w, blob.len() as u64)?;
+        w.write_all(&blob)
+    } else {
+        // 0 => raw bytes
+        w.write_all(&[0])?;
+        write_varint(w, bytes.len() as u64)?;
+        w.write_all(bytes)
+    }
+}
+
+fn read_value(r: &mut impl Read) -> io::Result<WrappedValue> {
+    let mut flag = [0u8; 1];
+    r.read_exact(&mut flag)?;
+    match flag[0] {
+        0 => {
+            let len = checked_len(read_varint(r)?)?;
+            let mut buf = vec![0u8; len];
+            r.read_exact(&mut buf)?;
+            Ok(WrappedValue::from_bytes(buf))
+        }
+        1 => {
+            let uncompressed = checked_len(read_varint(r)?)?;
+            let len = checked_len(read_varint(r)?)?;
+            let mut blob = vec![0u8; len];
+            r.read_exact(&mut blob)?;
+
+            // a blob of `len` bytes can't legitimately inflate to more than
+            // `len * MAX_COMPRESS_RATIO`; a larger claimed size is a bogus
+            // hint we refuse to pre-allocate for
+            if uncompressed > len.saturating_mul(MAX_COMPRESS_RATIO) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "implausible uncompressed length",
+                ));
+            }
+
+            // cap the inflate itself so a zip-bomb blob can't expand past
+            // the advertised (and now bounded) size
+            let mut buf = Vec::new();
+            ZlibDecoder::new(&blob[..])
+                .take(uncompressed as u64)
+                .read_to_end(&mut buf)?;
+            Ok(WrappedValue::from_bytes(buf))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid value compression flag",
+        )),
+    }
+}
+
+fn write_hello(w: &mut impl Write, version: u32, capabilities: &[String]) -> io::Result<()> {
+    write_varint(w, version as u64)?;
+    write_varint(w, capabilities.len() as u64)?;
+    for cap in capabilities {
+        write_string(w, cap)?;
+    }
+    Ok(())
+}
+
+fn read_hello(r: &mut impl Read) -> io::Result<(u32, Vec<String>)> {
+    let version = read_varint(r)? as u32;
+    let count = read_varint(r)? as usize;
+    let mut capabilities = Vec::with_capacity(count);
+    for _ in 0..count {
+        capabilities.push(read_string(r)?);
+    }
+    Ok((version, capabilities))
+}
+
+impl Command {
+    pub fn write_frame(&self, w: &mut impl Write) -> io::Result<()> {
+        match *self {
+            Command::Hello {
+                version,
+                ref capabilities,
+            } => {
+                w.write_all(&[opcode::HEL])?;
+                write_hello(w, version, capabilities)
+            }
+            Command::Get { ref key } => {
+                w.write_all(&[opcode::GET])?;
+                write_string(w, key)
+            }
+            Command::Set {
+                ref key,
+                ref value,
+                ttl,
+            } => {
+                w.write_all(&[opcode::SET])?;
+                write_string(w, key)?;
+                write_ttl(w, ttl)?;
+                write_value(w, value)
+            }
+            Command::Delete { ref key } => {
+                w.write_all(&[opcode::DEL])?;
+                write_string(w, key)
+            }
+            Command::Dump => w.write_all(&[opcode::DMP]),
+            Command::Quit => w.write_all(&[opcode::EXT]),
+            Command::Watch { ref key } => {
+                w.write_all(&[opcode::WCH])?;
+                write_string(w, key)
+            }
+        }
+    }
+
+    pub fn read_frame(r: &mut impl Read) -> io::Result<Self> {
+        let mut op = [0u8; 1];
+        r.read_exact(&mut op)?;
+        match op[0] {
+            opcode::HEL => {
+                let (version, capabilities) = read_hello(r)?;
+                Ok(Command::Hello {
+                    version,
+                    capabilities,
+                })
+            }
+            opcode::GET => Ok(Command::Get {
+                key: read_string(r)?,
+            }),
+            opcode::SET => {
+                let key = read_string(r)?;
+                let ttl = read_ttl(r)?;
+                let value = read_value(r)?;
+                Ok(Command::Set { key, value, ttl })
+            }
+            opcode::DEL => Ok(Command::Delete {
+                key: read_string(r)?,
+            }),
+            opcode::DMP => Ok(Command::Dump),
+            opcode::EXT => Ok(Command::Quit),
+            opcode::WCH => Ok(Command::Watch {
+                key: read_string(r)?,
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid command opcode",
+            )),
+        }
+    }
+}
+
+impl Response {
+    pub fn write_frame(&self, w: &mut impl Write) -> io::Result<()> {
+        match *self {
+            Response::Hello {
+                version,
+                ref capabilities,
+            } => {
+                w.write_all(&[opcode::HEL])?;
+                write_hello(w, version, capabilities)
+            }
+            Response::Value(ref value) => {
+                w.write_all(&[opcode::VAL])?;
+                write_value(w, value)
+            }
+            Response::Err(ref error) => {
+                w.write_all(&[opcode::ERR])?;
+                write_string(w, error)
+            }
+            Response::Ok => w.write_all(&[opcode::OK]),
+            Response::Event {
+                ref key,
+                ref value,
+            } => {
+                w.write_all(&[opcode::EVT])?;
+                write_string(w, key)?;
+                write_value(w, value)
+            }
+        }
+    }
+
+    pub fn read_frame(r: &mut impl Read) -> io::Result<Self> {
+        let mut op = [0u8; 1];
+        r.read_exact(&mut op)?;
+        match op[0] {
+            opcode::HEL => {
+                let (version, capabilities) = read_hello(r)?;
+                Ok(Response::Hello {
+                    version,
+                    capabilities,
+                })
+            }
+            opcode::VAL => Ok(Response::Value(read_value(r)?)),
+            opcode::ERR => Ok(Response::Err(read_string(r)?)),
+            opcode::OK => Ok(Response::Ok),
+            opcode::EVT => Ok(Response::Event {
+                key: read_string(r)?,
+                value: read_value(r)?,
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid response opcode",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint_roundtrip(n: u64) -> u64 {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, n).unwrap();
+        read_varint(&mut &buf[..]).unwrap()
+    }
+
+    #[test]
+    fn varint_roundtrips_across_byte_boundaries() {
+        for &n in &[0, 1, 127, 128, 300, 16_384, u32::MAX as u64, u64::MAX] {
+            assert_eq!(varint_roundtrip(n), n);
+        }
+    }
+
+    #[test]
+    fn command_frames_roundtrip() {
+        let mut buf = Vec::new();
+        Command::Get { key: "foo".into() }
+            .write_frame(&mut buf)
+            .unwrap();
+        match Command::read_frame(&mut &buf[..]).unwrap() {
+            Command::Get { key } => assert_eq!(key, "foo"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let mut buf = Vec::new();
+        Command::Set {
+            key: "k".into(),
+            value: WrappedValue::from_string("v".into()),
+            ttl: Some(42),
+        }
+        .write_frame(&mut buf)
+        .unwrap();
+        match Command::read_frame(&mut &buf[..]).unwrap() {
+            Command::Set { key, value, ttl } => {
+                assert_eq!(key, "k");
+                assert_eq!(value.into_inner(), "v");
+                assert_eq!(ttl, Some(42));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hello_frame_preserves_version_and_capabilities() {
+        let mut buf = Vec::new();
+        Command::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: vec!["binary".into(), "ttl".into()],
+        }
+        .write_frame(&mut buf)
+        .unwrap();
+        match Command::read_frame(&mut &buf[..]).unwrap() {
+            Command::Hello {
+                version,
+                capabilities,
+            } => {
+                assert_eq!(version, PROTOCOL_VERSION);
+                assert_eq!(capabilities, vec!["binary", "ttl"]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn event_frame_roundtrips() {
+        let mut buf = Vec::new();
+        Response::Event {
+            key: "k".into(),
+            value: WrappedValue::from_string("v".into()),
+        }
+        .write_frame(&mut buf)
+        .unwrap();
+        match Response::read_frame(&mut &buf[..]).unwrap() {
+            Response::Event { key, value } => {
+                assert_eq!(key, "k");
+                assert_eq!(value.into_inner(), "v");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn value_with_newline_roundtrips() {
+        // the hazard the binary codec exists to fix
+        let payload = "line one\nline two\n".to_string();
+        let mut buf = Vec::new();
+        Response::Value(WrappedValue::from_string(payload.clone()))
+            .write_frame(&mut buf)
+            .unwrap();
+        match Response::read_frame(&mut &buf[..]).unwrap() {
+            Response::Value(value) => assert_eq!(value.into_inner(), payload),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn large_value_is_compressed_but_roundtrips() {
+        let payload = "x".repeat(COMPRESS_THRESHOLD * 4);
+        let mut buf = Vec::new();
+        Response::Value(WrappedValue::from_string(payload.clone()))
+            .write_frame(&mut buf)
+            .unwrap();
+
+        // a compressible payload well over the threshold should shrink
+        assert!(buf.len() < payload.len());
+
+        match Response::read_frame(&mut &buf[..]).unwrap() {
+            Response::Value(value) => assert_eq!(value.into_inner(), payload),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}